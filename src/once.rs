@@ -14,7 +14,13 @@ use stable::{AtomicU8, ATOMIC_U8_INIT, Ordering, fence};
 #[cfg(not(feature = "nightly"))]
 type U8 = usize;
 use std::mem;
-use parking_lot_core::{self, SpinWait, DEFAULT_PARK_TOKEN, DEFAULT_UNPARK_TOKEN};
+use std::cell::UnsafeCell;
+use std::ops::Deref;
+use std::panic;
+use std::ptr;
+use std::sync::atomic::AtomicPtr;
+use std::time::{Duration, Instant};
+use parking_lot_core::{self, ParkResult, SpinWait, DEFAULT_PARK_TOKEN, DEFAULT_UNPARK_TOKEN};
 use util::UncheckedOptionExt;
 
 const DONE_BIT: U8 = 1;
@@ -24,7 +30,7 @@ const PARKED_BIT: U8 = 8;
 
 /// State yielded to the `call_once_force` method which can be used to query
 /// whether the `Once` was previously poisoned or not.
-pub struct OnceState(bool);
+pub struct OnceState(U8);
 
 impl OnceState {
     /// Returns whether the associated `Once` has been poisoned.
@@ -33,10 +39,26 @@ impl OnceState {
     /// indicate to future forced initialization routines that it is poisoned.
     #[inline]
     pub fn poisoned(&self) -> bool {
-        self.0
+        self.0 & POISON_BIT != 0
     }
 }
 
+/// The state of a `Once`, as observed non-destructively via `Once::state`.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum OnceState2 {
+    /// The `Once` has not been initialized and no initialization routine is
+    /// currently running.
+    New,
+    /// An initialization routine is currently running.
+    InProgress,
+    /// An initialization routine ran but panicked, so the `Once` is
+    /// poisoned. `call_once` will panic, but `call_once_force` can still
+    /// run another initialization routine.
+    Poisoned,
+    /// An initialization routine has previously completed successfully.
+    Done,
+}
+
 /// A synchronization primitive which can be used to run a one-time
 /// initialization. Useful for one-time initialization for globals, FFI or
 /// related functionality.
@@ -138,7 +160,38 @@ impl Once {
         }
 
         let mut f = Some(f);
-        self.call_once_slow(false, &mut |_| unsafe { f.take().unchecked_unwrap()() });
+        self.call_once_slow(false, None, &mut |_| unsafe { f.take().unchecked_unwrap()() });
+    }
+
+    /// Performs the same function as `call_once` except the closure will not
+    /// be run if `timeout` elapses before the lock can be acquired.
+    ///
+    /// Returns `true` if the `Once` was completed, or `false` if the
+    /// `timeout` elapsed while waiting for another thread's initialization
+    /// routine to finish. In the latter case no closure is run: the thread
+    /// that actually holds the lock always runs its closure to completion,
+    /// only waiters can time out.
+    #[inline]
+    pub fn call_once_for<F>(&self, timeout: Duration, f: F) -> bool
+        where F: FnOnce()
+    {
+        self.call_once_until(Instant::now() + timeout, f)
+    }
+
+    /// Performs the same function as `call_once_for` except the timeout is
+    /// an absolute instant instead of a duration.
+    #[inline]
+    pub fn call_once_until<F>(&self, deadline: Instant, f: F) -> bool
+        where F: FnOnce()
+    {
+        if self.0.load(Ordering::Acquire) == DONE_BIT {
+            return true;
+        }
+
+        let mut f = Some(f);
+        self.call_once_slow(false,
+                            Some(deadline),
+                            &mut |_| unsafe { f.take().unchecked_unwrap()() })
     }
 
     /// Performs the same function as `call_once` except ignores poisoning.
@@ -160,11 +213,40 @@ impl Once {
 
         let mut f = Some(f);
         self.call_once_slow(true,
+                            None,
                             &mut |state| unsafe {
                                 f.take().unchecked_unwrap()(state)
                             });
     }
 
+    /// Returns whether the associated initialization routine has been run
+    /// and completed successfully.
+    ///
+    /// This is a single `Acquire` load and never blocks, unlike
+    /// `call_once`.
+    #[inline]
+    pub fn is_completed(&self) -> bool {
+        self.0.load(Ordering::Acquire) & DONE_BIT != 0
+    }
+
+    /// Returns the current state of this `Once`.
+    ///
+    /// Like `is_completed`, this never blocks and never runs an
+    /// initialization routine.
+    #[inline]
+    pub fn state(&self) -> OnceState2 {
+        let state = self.0.load(Ordering::Acquire);
+        if state & DONE_BIT != 0 {
+            OnceState2::Done
+        } else if state & LOCKED_BIT != 0 {
+            OnceState2::InProgress
+        } else if state & POISON_BIT != 0 {
+            OnceState2::Poisoned
+        } else {
+            OnceState2::New
+        }
+    }
+
     // This is a non-generic function to reduce the monomorphization cost of
     // using `call_once` (this isn't exactly a trivial or small implementation).
     //
@@ -178,7 +260,11 @@ impl Once {
     // without some allocation overhead.
     #[cold]
     #[inline(never)]
-    fn call_once_slow(&self, ignore_poison: bool, f: &mut FnMut(OnceState)) {
+    fn call_once_slow(&self,
+                      ignore_poison: bool,
+                      timeout: Option<Instant>,
+                      f: &mut FnMut(OnceState))
+                      -> bool {
         let mut spinwait = SpinWait::new();
         let mut state = self.0.load(Ordering::Relaxed);
         loop {
@@ -187,7 +273,7 @@ impl Once {
                 // An acquire fence is needed here since we didn't load the
                 // state with Ordering::Acquire.
                 fence(Ordering::Acquire);
-                return;
+                return true;
             }
 
             // If the state has been poisoned and we aren't forcing, then panic
@@ -235,13 +321,25 @@ impl Once {
                 let addr = self as *const _ as usize;
                 let validate = || self.0.load(Ordering::Relaxed) == LOCKED_BIT | PARKED_BIT;
                 let before_sleep = || {};
-                let timed_out = |_, _| unreachable!();
-                parking_lot_core::park(addr,
-                                       validate,
-                                       before_sleep,
-                                       timed_out,
-                                       DEFAULT_PARK_TOKEN,
-                                       None);
+                let timed_out = |_, was_last_thread| {
+                    // Clear the parked bit if we were the last parked thread
+                    if was_last_thread {
+                        self.0.fetch_and(!PARKED_BIT, Ordering::Relaxed);
+                    }
+                };
+                match parking_lot_core::park(addr,
+                                             validate,
+                                             before_sleep,
+                                             timed_out,
+                                             DEFAULT_PARK_TOKEN,
+                                             timeout) {
+                    // We were unparked, so the lock state has changed; loop
+                    // back around and check it again.
+                    ParkResult::Unparked(_) | ParkResult::Invalid => (),
+
+                    // We timed out without the closure ever running.
+                    ParkResult::TimedOut => return false,
+                }
             }
 
             // Loop back and check if the done bit was set
@@ -267,7 +365,7 @@ impl Once {
         // At this point we have the lock, so run the closure. Make sure we
         // properly clean up if the closure panicks.
         let guard = PanicGuard(self);
-        f(OnceState(state & POISON_BIT != 0));
+        f(OnceState(state));
         mem::forget(guard);
 
         // Now unlock the state, set the done bit and unpark all threads
@@ -278,6 +376,7 @@ impl Once {
                 parking_lot_core::unpark_all(addr, DEFAULT_UNPARK_TOKEN);
             }
         }
+        true
     }
 }
 
@@ -288,13 +387,321 @@ impl Default for Once {
     }
 }
 
+/// A cell which can be written to only once, backed by a `Once`.
+///
+/// This is a more ergonomic alternative to pairing a `Once` with a
+/// `static mut`: the value is synchronized by the same adaptive-spinning
+/// `Once` machinery, so reading it back never requires `unsafe`.
+///
+/// # Size and allocation
+///
+/// Unlike `Once`, which stores no value and needs only a single byte,
+/// `OnceCell<T>` stores `T` in its own heap allocation behind an
+/// `AtomicPtr<T>` rather than inline. This costs an allocation (and a
+/// pointer indirection on every `get`) for every initialized cell, even a
+/// small `Copy` one, but it is what lets `get_or_init_race` publish a value with a
+/// single lock-free `compare_exchange` instead of taking the `Once` lock.
+///
+/// # Examples
+///
+/// ```
+/// use parking_lot::OnceCell;
+///
+/// let cell = OnceCell::new();
+///
+/// let value = cell.get_or_init(|| 1 + 1);
+/// assert_eq!(*value, 2);
+/// ```
+pub struct OnceCell<T> {
+    once: Once,
+    value: AtomicPtr<T>,
+}
+
+unsafe impl<T: Send> Send for OnceCell<T> {}
+unsafe impl<T: Send + Sync> Sync for OnceCell<T> {}
+
+impl<T> Drop for OnceCell<T> {
+    fn drop(&mut self) {
+        let ptr = *self.value.get_mut();
+        if !ptr.is_null() {
+            unsafe {
+                drop(Box::from_raw(ptr));
+            }
+        }
+    }
+}
+
+impl<T> Default for OnceCell<T> {
+    #[inline]
+    fn default() -> OnceCell<T> {
+        OnceCell::new()
+    }
+}
+
+impl<T> OnceCell<T> {
+    /// Creates a new empty cell.
+    #[cfg(feature = "nightly")]
+    #[inline]
+    pub const fn new() -> OnceCell<T> {
+        OnceCell {
+            once: Once::new(),
+            value: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    /// Creates a new empty cell.
+    #[cfg(not(feature = "nightly"))]
+    #[inline]
+    pub fn new() -> OnceCell<T> {
+        OnceCell {
+            once: Once::new(),
+            value: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    /// Returns a reference to the value in the cell, or `None` if it hasn't
+    /// been initialized yet.
+    ///
+    /// This never blocks: it is a single fast-path load of the cell's
+    /// pointer, regardless of which of `set`, `get_or_init` or
+    /// `get_or_init_race` ends up publishing the value.
+    #[inline]
+    pub fn get(&self) -> Option<&T> {
+        let ptr = self.value.load(Ordering::Acquire);
+        if ptr.is_null() {
+            None
+        } else {
+            Some(unsafe { &*ptr })
+        }
+    }
+
+    // Tries to publish `new` into the cell with a single `compare_exchange`.
+    // Returns a reference into whichever box ends up owned by the cell if
+    // `new` won the race, or hands `new` back untouched if another writer
+    // (`set`, `get_or_init` or `get_or_init_race`) got there first.
+    #[inline]
+    fn publish(&self, new: Box<T>) -> Result<&T, Box<T>> {
+        let new = Box::into_raw(new);
+        match self.value
+            .compare_exchange(ptr::null_mut(), new, Ordering::AcqRel, Ordering::Acquire) {
+            Ok(_) => Ok(unsafe { &*new }),
+            Err(_) => Err(unsafe { Box::from_raw(new) }),
+        }
+    }
+
+    /// Sets the contents of this cell to `value`.
+    ///
+    /// Returns `Ok(())` if the cell was empty, or `Err(value)` if it was
+    /// already initialized (in which case `value` is simply handed back to
+    /// the caller).
+    pub fn set(&self, value: T) -> Result<(), T> {
+        if self.get().is_some() {
+            return Err(value);
+        }
+
+        let mut slot = Some(value);
+        let mut lost = None;
+        self.once.call_once_force(|_| {
+            if let Err(boxed) = self.publish(Box::new(slot.take().unwrap())) {
+                // We were the lock holder, but a concurrent
+                // `get_or_init_race` call published first.
+                lost = Some(*boxed);
+            }
+        });
+        match slot {
+            // `call_once_force` didn't run our closure: the cell was already set.
+            Some(value) => Err(value),
+            None => match lost {
+                Some(value) => Err(value),
+                None => Ok(()),
+            },
+        }
+    }
+
+    /// Returns a reference to the value in the cell, initializing it with
+    /// `f` if the cell is empty.
+    ///
+    /// If many threads call `get_or_init` concurrently with different
+    /// initializing functions, only one of them runs: the others block
+    /// until it completes and then observe its result, exactly like
+    /// `Once::call_once`.
+    ///
+    /// # Panics
+    ///
+    /// If `f` panics, the panic is propagated to the caller and the cell
+    /// remains uninitialized. The underlying `Once` is then poisoned, so
+    /// later calls to `get_or_init` on this cell panic as well, as do calls
+    /// made after a `get_or_try_init` whose `f` returned `Err`.
+    #[inline]
+    pub fn get_or_init<F>(&self, f: F) -> &T
+        where F: FnOnce() -> T
+    {
+        if let Some(value) = self.get() {
+            return value;
+        }
+        let mut f = Some(f);
+        self.once.call_once(|| {
+            let value = unsafe { f.take().unchecked_unwrap()() };
+            let _ = self.publish(Box::new(value));
+        });
+        self.get().unwrap()
+    }
+
+    /// Returns a reference to the value in the cell, attempting to
+    /// initialize it with `f` if the cell is empty.
+    ///
+    /// Unlike `get_or_init`, `f` is allowed to fail. If it returns `Err`,
+    /// the cell is left uninitialized so that a later `get_or_try_init` or
+    /// `set` may try again; `get_or_init` panics on such a cell.
+    pub fn get_or_try_init<F, E>(&self, f: F) -> Result<&T, E>
+        where F: FnOnce() -> Result<T, E>
+    {
+        if let Some(value) = self.get() {
+            return Ok(value);
+        }
+
+        // `call_once`/`call_once_force` have no notion of a fallible
+        // initializer: once the closure returns normally the `Once` is
+        // marked done. To let a failed `f` leave the cell uninitialized
+        // for a future retry, we turn `Err` into a panic that we catch
+        // here, which makes `call_once_force` poison the `Once` instead of
+        // completing it. `call_once_force` ignores that poison, so the
+        // next `get_or_try_init` simply tries again.
+        let mut f = Some(f);
+        let mut error = None;
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            self.once.call_once_force(|_| {
+                match f.take().unwrap()() {
+                    Ok(value) => {
+                        let _ = self.publish(Box::new(value));
+                    }
+                    Err(e) => {
+                        error = Some(e);
+                        panic!("parking_lot::OnceCell initializer failed");
+                    }
+                }
+            });
+        }));
+        if let Err(payload) = result {
+            if error.is_none() {
+                // A genuine panic from `f`, not our synthetic one: let it
+                // keep propagating.
+                panic::resume_unwind(payload);
+            }
+        }
+        match error {
+            Some(e) => Err(e),
+            None => Ok(self.get().unwrap()),
+        }
+    }
+
+    /// Returns a reference to the value in the cell, racing to initialize
+    /// it with `f` if the cell is empty, à la Windows'
+    /// `InitOnceExecuteOnce(INIT_ONCE_ASYNC)`.
+    ///
+    /// Unlike `get_or_init`, callers never block on each other: every
+    /// thread that observes the cell empty runs `f` and then races the
+    /// others to publish its result with a single `compare_exchange`. The
+    /// first one to win is kept forever; everyone else's freshly computed
+    /// value is simply dropped in favor of the winner's, which is read back
+    /// through an `Acquire` load/fence.
+    ///
+    /// This trades "`f` runs exactly once" for "no thread ever blocks on
+    /// another's initialization", which is only worth it when `f` is cheap,
+    /// side-effect-free and idempotent. There is no poisoning in this mode:
+    /// a panic in `f` simply propagates to its own caller without affecting
+    /// any other racer.
+    pub fn get_or_init_race<F>(&self, f: F) -> &T
+        where F: FnOnce() -> T
+    {
+        if let Some(value) = self.get() {
+            return value;
+        }
+        match self.publish(Box::new(f())) {
+            Ok(value) => value,
+            // Another racer published first; drop our own value (done by
+            // the `Box<T>` going out of scope here) and read theirs.
+            Err(_) => self.get().unwrap(),
+        }
+    }
+}
+
+/// A value which is lazily initialized on first access, and then cached.
+///
+/// This pairs a `OnceCell` with a stored initializing function, so that a
+/// global can be declared once and then used as if it were the value
+/// itself via `Deref`.
+///
+/// # Examples
+///
+/// ```
+/// use parking_lot::Lazy;
+///
+/// let value = Lazy::new(|| 1 + 1);
+///
+/// assert_eq!(*value, 2);
+/// ```
+pub struct Lazy<T, F = fn() -> T> {
+    cell: OnceCell<T>,
+    init: UnsafeCell<Option<F>>,
+}
+
+unsafe impl<T, F: Send> Sync for Lazy<T, F> where OnceCell<T>: Sync {}
+
+impl<T, F> Lazy<T, F> {
+    /// Creates a new lazy value with the given initializing function.
+    #[cfg(feature = "nightly")]
+    #[inline]
+    pub const fn new(f: F) -> Lazy<T, F> {
+        Lazy {
+            cell: OnceCell::new(),
+            init: UnsafeCell::new(Some(f)),
+        }
+    }
+
+    /// Creates a new lazy value with the given initializing function.
+    #[cfg(not(feature = "nightly"))]
+    #[inline]
+    pub fn new(f: F) -> Lazy<T, F> {
+        Lazy {
+            cell: OnceCell::new(),
+            init: UnsafeCell::new(Some(f)),
+        }
+    }
+}
+
+impl<T, F: FnOnce() -> T> Lazy<T, F> {
+    /// Forces evaluation of this lazy value and returns a reference to the
+    /// result.
+    ///
+    /// This is what `Deref` calls internally; most users should just use
+    /// `*lazy` instead.
+    #[inline]
+    pub fn force(this: &Lazy<T, F>) -> &T {
+        this.cell.get_or_init(|| {
+            let f = unsafe { (*this.init.get()).take().unchecked_unwrap() };
+            f()
+        })
+    }
+}
+
+impl<T, F: FnOnce() -> T> Deref for Lazy<T, F> {
+    type Target = T;
+
+    #[inline]
+    fn deref(&self) -> &T {
+        Lazy::force(self)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     #[cfg(feature = "nightly")]
     use std::panic;
     use std::sync::mpsc::channel;
     use std::thread;
-    use {Once, ONCE_INIT};
+    use std::time::Duration;
+    use {Lazy, Once, OnceCell, OnceState2, ONCE_INIT};
 
     #[test]
     fn smoke_once() {
@@ -371,6 +778,40 @@ mod tests {
         O.call_once(|| {});
     }
 
+    // `call_once_force` only ever invokes its closure while the `Once` is
+    // *not* done, whether that's because it was never run or because it was
+    // poisoned: a prior successful completion short-circuits before the
+    // closure ever runs again. So `OnceState` can only ever report
+    // `poisoned()`, never "previously completed".
+    #[cfg(feature = "nightly")]
+    #[test]
+    fn call_once_force_after_completion_never_runs() {
+        static O: Once = ONCE_INIT;
+        O.call_once(|| {});
+
+        let mut called = false;
+        O.call_once_force(|_| called = true);
+        assert!(!called);
+    }
+
+    #[cfg(feature = "nightly")]
+    #[test]
+    fn call_once_force_after_poison_sees_poisoned() {
+        static O: Once = ONCE_INIT;
+
+        let t = panic::catch_unwind(|| {
+            O.call_once(|| panic!());
+        });
+        assert!(t.is_err());
+
+        let mut called = false;
+        O.call_once_force(|state| {
+            called = true;
+            assert!(state.poisoned());
+        });
+        assert!(called);
+    }
+
     #[cfg(feature = "nightly")]
     #[test]
     fn wait_for_force_to_finish() {
@@ -410,4 +851,151 @@ mod tests {
         assert!(t2.join().is_ok());
 
     }
+
+    #[test]
+    fn once_state() {
+        static O: Once = ONCE_INIT;
+        assert_eq!(O.state(), OnceState2::New);
+        assert!(!O.is_completed());
+
+        O.call_once(|| {});
+        assert_eq!(O.state(), OnceState2::Done);
+        assert!(O.is_completed());
+    }
+
+    #[cfg(feature = "nightly")]
+    #[test]
+    fn once_state_poisoned() {
+        static O: Once = ONCE_INIT;
+        let t = panic::catch_unwind(|| {
+            O.call_once(|| panic!());
+        });
+        assert!(t.is_err());
+        assert_eq!(O.state(), OnceState2::Poisoned);
+        assert!(!O.is_completed());
+    }
+
+    #[test]
+    fn call_once_for_completes() {
+        static O: Once = ONCE_INIT;
+        let mut a = 0;
+        assert!(O.call_once_for(Duration::from_millis(100), || a += 1));
+        assert_eq!(a, 1);
+        assert!(O.call_once_for(Duration::from_millis(100), || a += 1));
+        assert_eq!(a, 1);
+    }
+
+    #[test]
+    fn call_once_for_times_out() {
+        static O: Once = ONCE_INIT;
+        let (tx1, rx1) = channel();
+        let (tx2, rx2) = channel();
+        let t = thread::spawn(move || {
+            O.call_once(|| {
+                tx1.send(()).unwrap();
+                rx2.recv().unwrap();
+            });
+        });
+
+        rx1.recv().unwrap();
+
+        let mut called = false;
+        let completed = O.call_once_for(Duration::from_millis(50), || called = true);
+        assert!(!completed);
+        assert!(!called);
+
+        tx2.send(()).unwrap();
+        assert!(t.join().is_ok());
+
+        // The waiter's timeout should not have left the `Once` wedged: it
+        // can still be completed afterwards.
+        let mut called2 = false;
+        assert!(O.call_once_for(Duration::from_millis(100), || called2 = true));
+        assert!(!called2);
+    }
+
+    #[test]
+    fn once_cell_get_or_init() {
+        let cell: OnceCell<usize> = OnceCell::new();
+        assert!(cell.get().is_none());
+
+        let mut calls = 0;
+        let value = cell.get_or_init(|| {
+            calls += 1;
+            42
+        });
+        assert_eq!(*value, 42);
+
+        let value = cell.get_or_init(|| {
+            calls += 1;
+            0
+        });
+        assert_eq!(*value, 42);
+        assert_eq!(calls, 1);
+        assert_eq!(cell.get(), Some(&42));
+    }
+
+    #[test]
+    fn once_cell_set() {
+        let cell: OnceCell<usize> = OnceCell::new();
+        assert_eq!(cell.set(1), Ok(()));
+        assert_eq!(cell.set(2), Err(2));
+        assert_eq!(cell.get(), Some(&1));
+    }
+
+    #[test]
+    fn once_cell_get_or_try_init() {
+        let cell: OnceCell<usize> = OnceCell::new();
+
+        let result: Result<_, ()> = cell.get_or_try_init(|| Err(()));
+        assert!(result.is_err());
+        assert!(cell.get().is_none());
+
+        let result = cell.get_or_try_init(|| Ok::<_, ()>(7));
+        assert_eq!(result, Ok(&7));
+        assert_eq!(cell.get(), Some(&7));
+    }
+
+    #[test]
+    fn once_cell_get_or_init_race() {
+        use std::sync::Arc;
+
+        let cell = Arc::new(OnceCell::new());
+
+        let (tx, rx) = channel();
+        for i in 0..10 {
+            let tx = tx.clone();
+            let cell = cell.clone();
+            thread::spawn(move || {
+                for _ in 0..4 {
+                    thread::yield_now();
+                }
+                tx.send(*cell.get_or_init_race(|| i)).unwrap();
+            });
+        }
+
+        let mut results = Vec::new();
+        for _ in 0..10 {
+            results.push(rx.recv().unwrap());
+        }
+
+        // Every thread must agree on a single winning value.
+        assert!(results.iter().all(|&v| v == results[0]));
+        assert_eq!(cell.get(), Some(&results[0]));
+    }
+
+    #[cfg(feature = "nightly")]
+    #[test]
+    fn lazy_smoke() {
+        static LAZY: Lazy<usize> = Lazy::new(|| 1 + 1);
+        assert_eq!(*LAZY, 2);
+        assert_eq!(*LAZY, 2);
+    }
+
+    #[test]
+    fn lazy_non_static() {
+        let lazy = Lazy::new(|| 1 + 1);
+        assert_eq!(*lazy, 2);
+        assert_eq!(*lazy, 2);
+    }
 }